@@ -1,9 +1,867 @@
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use regex::Regex;  // For better parsing (add dependency if needed, but assume available)
 
+// Hand-rolled, nom-style combinator parser: small, composable functions that
+// each consume a prefix of the input and return the rest plus whatever they
+// parsed. No external parser-combinator crate required.
+mod parser {
+    use std::fmt;
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum Operand {
+        Reg(usize),
+        Imm(i64),
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum MemOperand {
+        Base(usize),
+        BaseImm(usize, i64),
+        BaseReg(usize, usize),
+    }
+
+    /// Condition under which a predicated data-processing op executes, or a
+    /// conditional branch is taken. Evaluated against the `Z`/`N`/`C`/`V`
+    /// flags, mirroring how predicated architectures gate execution on a
+    /// condition register.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Condition {
+        Always,
+        Eq,
+        Ne,
+        Gt,
+        Ge,
+        Lt,
+        Le,
+        Hi,
+        Lo,
+    }
+
+    /// Lane width used by the packed vector ALU ops. `Qword` (the default,
+    /// no `.b`/`.h`/`.w` suffix) treats a 128-bit vector register as the two
+    /// native `u64` lanes it's physically stored as.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum VecWidth {
+        Byte,
+        Half,
+        Word,
+        Qword,
+    }
+
+    impl VecWidth {
+        pub fn lane_bytes(self) -> usize {
+            match self {
+                VecWidth::Byte => 1,
+                VecWidth::Half => 2,
+                VecWidth::Word => 4,
+                VecWidth::Qword => 8,
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum Instruction {
+        Movi { rd: usize, imm: i64, cond: Condition },
+        Load { rd: usize, mem: MemOperand },
+        Add { rd: usize, rs1: usize, operand: Operand, cond: Condition },
+        Cmp { rs1: usize, operand: Operand },
+        Dec { rd: usize },
+        Branch { cond: Condition, label: String },
+        Snd { rs: usize },
+        Rcv { rd: usize },
+        Vload { vd: usize, mem: MemOperand },
+        Vstore { vs: usize, mem: MemOperand },
+        Vdup { vd: usize, rs: usize },
+        Vadd { vd: usize, va: usize, vb: usize, width: VecWidth },
+        Vsub { vd: usize, va: usize, vb: usize, width: VecWidth },
+        Vmul { vd: usize, va: usize, vb: usize, width: VecWidth },
+        Label(String),
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct ParseError {
+        pub line: usize,
+        pub message: String,
+    }
+
+    impl fmt::Display for ParseError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "line {}: {}", self.line, self.message)
+        }
+    }
+
+    impl std::error::Error for ParseError {}
+
+    type ParseResult<'a, T> = Result<(&'a str, T), String>;
+
+    fn skip_ws(input: &str) -> &str {
+        input.trim_start_matches(|c: char| c.is_whitespace() || c == ',')
+    }
+
+    /// Consumes a run of characters matching `pred`, failing on an empty match.
+    fn take_while<'a>(input: &'a str, pred: impl Fn(char) -> bool) -> ParseResult<'a, &'a str> {
+        let end = input.find(|c: char| !pred(c)).unwrap_or(input.len());
+        if end == 0 {
+            return Err("expected token".to_string());
+        }
+        Ok((&input[end..], &input[..end]))
+    }
+
+    /// Opcodes may carry a `.b`/`.h`/`.w` vector-width suffix (`VADD.W`), so
+    /// `.` is accepted as part of the token here and split off below.
+    fn opcode(input: &str) -> ParseResult<'_, String> {
+        let (rest, tok) = take_while(input, |c| c.is_alphanumeric() || c == '_' || c == '.')?;
+        Ok((rest, tok.to_uppercase()))
+    }
+
+    fn vec_width(suffix: &str) -> Result<VecWidth, String> {
+        match suffix {
+            "B" => Ok(VecWidth::Byte),
+            "H" => Ok(VecWidth::Half),
+            "W" => Ok(VecWidth::Word),
+            other => Err(format!("unknown vector width suffix '.{}'", other)),
+        }
+    }
+
+    /// Register file sizes, matching `CRZ64I_Emulator::regs`/`vregs`. Kept
+    /// here so an out-of-range `rN`/`vN` token is a parse error with a line
+    /// number rather than an out-of-bounds index panic at execution time.
+    const NUM_REGS: usize = 32;
+    const NUM_VREGS: usize = 8;
+
+    /// Parses `vN`, a 128-bit vector register token.
+    fn vreg(input: &str) -> ParseResult<'_, usize> {
+        let input = skip_ws(input);
+        let rest = input
+            .strip_prefix('v')
+            .ok_or_else(|| format!("expected vector register, got '{}'", input))?;
+        let (rest, digits) = take_while(rest, |c| c.is_ascii_digit())?;
+        let n: usize = digits
+            .parse()
+            .map_err(|_| format!("invalid vector register number '{}'", digits))?;
+        if n >= NUM_VREGS {
+            return Err(format!("vector register v{} out of range (only v0-v{})", n, NUM_VREGS - 1));
+        }
+        Ok((rest, n))
+    }
+
+    /// Parses `rN`, a scalar register token.
+    fn reg(input: &str) -> ParseResult<'_, usize> {
+        let input = skip_ws(input);
+        let rest = input
+            .strip_prefix('r')
+            .ok_or_else(|| format!("expected register, got '{}'", input))?;
+        let (rest, digits) = take_while(rest, |c| c.is_ascii_digit())?;
+        let n: usize = digits
+            .parse()
+            .map_err(|_| format!("invalid register number '{}'", digits))?;
+        if n >= NUM_REGS {
+            return Err(format!("register r{} out of range (only r0-r{})", n, NUM_REGS - 1));
+        }
+        Ok((rest, n))
+    }
+
+    /// Parses `#123`, `#-5` or `#0x10` (decimal or hex, optionally signed).
+    fn imm(input: &str) -> ParseResult<'_, i64> {
+        let input = skip_ws(input);
+        let rest = input
+            .strip_prefix('#')
+            .ok_or_else(|| format!("expected immediate, got '{}'", input))?;
+        let (neg, rest) = match rest.strip_prefix('-') {
+            Some(r) => (true, r),
+            None => (false, rest),
+        };
+        let (rest, value) = if let Some(hex) = rest.strip_prefix("0x") {
+            let (rest, digits) = take_while(hex, |c| c.is_ascii_hexdigit())?;
+            let v = i64::from_str_radix(digits, 16)
+                .map_err(|_| format!("invalid hex immediate '0x{}'", digits))?;
+            (rest, v)
+        } else {
+            let (rest, digits) = take_while(rest, |c| c.is_ascii_digit())?;
+            let v: i64 = digits
+                .parse()
+                .map_err(|_| format!("invalid immediate '{}'", digits))?;
+            (rest, v)
+        };
+        Ok((rest, if neg { -value } else { value }))
+    }
+
+    /// Register or immediate, i.e. the second source operand of an ALU op.
+    fn operand(input: &str) -> ParseResult<'_, Operand> {
+        let input = skip_ws(input);
+        if let Ok((rest, n)) = reg(input) {
+            return Ok((rest, Operand::Reg(n)));
+        }
+        let (rest, n) = imm(input)?;
+        Ok((rest, Operand::Imm(n)))
+    }
+
+    /// Parses `[r1]`, `[r1+8]`, `[r1+r2]` or `[r1-8]`.
+    fn mem_operand(input: &str) -> ParseResult<'_, MemOperand> {
+        let input = skip_ws(input);
+        let rest = input
+            .strip_prefix('[')
+            .ok_or_else(|| format!("expected '[', got '{}'", input))?;
+        let (rest, base) = reg(rest)?;
+        let rest = rest.trim_start();
+        if let Some(after_close) = rest.strip_prefix(']') {
+            return Ok((after_close, MemOperand::Base(base)));
+        }
+        let (sign, rest) = match rest.strip_prefix('+') {
+            Some(r) => (1i64, r),
+            None => match rest.strip_prefix('-') {
+                Some(r) => (-1i64, r),
+                None => {
+                    return Err(format!(
+                        "expected '+'/'-'/']' in memory operand, got '{}'",
+                        rest
+                    ))
+                }
+            },
+        };
+        let rest = rest.trim_start();
+        if let Ok((rest, off_reg)) = reg(rest) {
+            let rest = rest.trim_start();
+            let rest = rest
+                .strip_prefix(']')
+                .ok_or_else(|| format!("expected ']', got '{}'", rest))?;
+            return Ok((rest, MemOperand::BaseReg(base, off_reg)));
+        }
+        let (rest, digits) = take_while(rest, |c| c.is_ascii_digit())?;
+        let off: i64 = digits
+            .parse()
+            .map_err(|_| format!("invalid offset '{}'", digits))?;
+        let rest = rest.trim_start();
+        let rest = rest
+            .strip_prefix(']')
+            .ok_or_else(|| format!("expected ']', got '{}'", rest))?;
+        Ok((rest, MemOperand::BaseImm(base, sign * off)))
+    }
+
+    /// Strips a known condition-code suffix (`EQ`, `NE`, `GT`, ...) from a
+    /// data-processing opcode, e.g. `ADDEQ` -> (`ADD`, `Condition::Eq`).
+    /// Unconditional opcodes are returned unchanged with `Condition::Always`.
+    fn strip_condition(op: &str) -> (&str, Condition) {
+        const SUFFIXES: [(&str, Condition); 8] = [
+            ("EQ", Condition::Eq),
+            ("NE", Condition::Ne),
+            ("GT", Condition::Gt),
+            ("GE", Condition::Ge),
+            ("LT", Condition::Lt),
+            ("LE", Condition::Le),
+            ("HI", Condition::Hi),
+            ("LO", Condition::Lo),
+        ];
+        for (suffix, cond) in SUFFIXES {
+            if let Some(base) = op.strip_suffix(suffix) {
+                if base == "MOVI" || base == "ADD" {
+                    return (base, cond);
+                }
+            }
+        }
+        (op, Condition::Always)
+    }
+
+    /// Parses one assembly line into a typed [`Instruction`].
+    pub fn parse_line(line: &str, lineno: usize) -> Result<Instruction, ParseError> {
+        let trimmed = line.trim();
+        let err = |message: String| ParseError { line: lineno, message };
+
+        if let Some(label) = trimmed.strip_suffix(':') {
+            return Ok(Instruction::Label(label.trim().to_string()));
+        }
+
+        let (rest, op) = opcode(trimmed).map_err(err)?;
+        let (op_base, width_suffix) = match op.split_once('.') {
+            Some((base, suffix)) => (base.to_string(), Some(suffix.to_string())),
+            None => (op.clone(), None),
+        };
+        let (base, cond) = strip_condition(&op_base);
+        match base {
+            "MOVI" => {
+                let (rest, rd) = reg(rest).map_err(err)?;
+                let (_, imm) = imm(rest).map_err(err)?;
+                Ok(Instruction::Movi { rd, imm, cond })
+            }
+            "LOAD" => {
+                let (rest, rd) = reg(rest).map_err(err)?;
+                let (_, mem) = mem_operand(rest).map_err(err)?;
+                Ok(Instruction::Load { rd, mem })
+            }
+            "ADD" => {
+                let (rest, rd) = reg(rest).map_err(err)?;
+                let (rest, rs1) = reg(rest).map_err(err)?;
+                let (_, operand) = operand(rest).map_err(err)?;
+                Ok(Instruction::Add { rd, rs1, operand, cond })
+            }
+            "CMP" => {
+                let (rest, rs1) = reg(rest).map_err(err)?;
+                let (_, operand) = operand(rest).map_err(err)?;
+                Ok(Instruction::Cmp { rs1, operand })
+            }
+            "DEC" => {
+                let (_, rd) = reg(rest).map_err(err)?;
+                Ok(Instruction::Dec { rd })
+            }
+            "BEQ" | "BNE" | "BGT" | "BGE" | "BLT" | "BLE" | "BHI" | "BLO" => {
+                let label = skip_ws(rest).trim();
+                if label.is_empty() {
+                    return Err(err(format!("expected label after {}", base)));
+                }
+                let cond = match base {
+                    "BEQ" => Condition::Eq,
+                    "BNE" => Condition::Ne,
+                    "BGT" => Condition::Gt,
+                    "BGE" => Condition::Ge,
+                    "BLT" => Condition::Lt,
+                    "BLE" => Condition::Le,
+                    "BHI" => Condition::Hi,
+                    "BLO" => Condition::Lo,
+                    _ => unreachable!(),
+                };
+                Ok(Instruction::Branch { cond, label: label.to_string() })
+            }
+            "SND" => {
+                let (_, rs) = reg(rest).map_err(err)?;
+                Ok(Instruction::Snd { rs })
+            }
+            "RCV" => {
+                let (_, rd) = reg(rest).map_err(err)?;
+                Ok(Instruction::Rcv { rd })
+            }
+            "VLOAD" => {
+                let (rest, vd) = vreg(rest).map_err(err)?;
+                let (_, mem) = mem_operand(rest).map_err(err)?;
+                Ok(Instruction::Vload { vd, mem })
+            }
+            "VSTORE" => {
+                let (rest, vs) = vreg(rest).map_err(err)?;
+                let (_, mem) = mem_operand(rest).map_err(err)?;
+                Ok(Instruction::Vstore { vs, mem })
+            }
+            "VDUP" => {
+                let (rest, vd) = vreg(rest).map_err(err)?;
+                let (_, rs) = reg(rest).map_err(err)?;
+                Ok(Instruction::Vdup { vd, rs })
+            }
+            "VADD" | "VSUB" | "VMUL" => {
+                let width = width_suffix
+                    .as_deref()
+                    .map(|s| vec_width(&s.to_uppercase()))
+                    .transpose()
+                    .map_err(err)?
+                    .unwrap_or(VecWidth::Qword);
+                let (rest, vd) = vreg(rest).map_err(err)?;
+                let (rest, va) = vreg(rest).map_err(err)?;
+                let (_, vb) = vreg(rest).map_err(err)?;
+                match base {
+                    "VADD" => Ok(Instruction::Vadd { vd, va, vb, width }),
+                    "VSUB" => Ok(Instruction::Vsub { vd, va, vb, width }),
+                    "VMUL" => Ok(Instruction::Vmul { vd, va, vb, width }),
+                    _ => unreachable!(),
+                }
+            }
+            other => Err(err(format!("unknown opcode '{}'", other))),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn round_trips_a_data_processing_instruction() {
+            assert_eq!(
+                parse_line("ADD r1 r2 #8", 1).unwrap(),
+                Instruction::Add { rd: 1, rs1: 2, operand: Operand::Imm(8), cond: Condition::Always }
+            );
+            assert_eq!(
+                parse_line("ADDEQ r1 r2 r3", 1).unwrap(),
+                Instruction::Add { rd: 1, rs1: 2, operand: Operand::Reg(3), cond: Condition::Eq }
+            );
+        }
+
+        #[test]
+        fn round_trips_memory_operand_forms() {
+            assert_eq!(
+                parse_line("LOAD r4 [r1]", 1).unwrap(),
+                Instruction::Load { rd: 4, mem: MemOperand::Base(1) }
+            );
+            assert_eq!(
+                parse_line("LOAD r4 [r1+8]", 1).unwrap(),
+                Instruction::Load { rd: 4, mem: MemOperand::BaseImm(1, 8) }
+            );
+            assert_eq!(
+                parse_line("LOAD r4 [r1-8]", 1).unwrap(),
+                Instruction::Load { rd: 4, mem: MemOperand::BaseImm(1, -8) }
+            );
+            assert_eq!(
+                parse_line("LOAD r4 [r1+r2]", 1).unwrap(),
+                Instruction::Load { rd: 4, mem: MemOperand::BaseReg(1, 2) }
+            );
+        }
+
+        #[test]
+        fn round_trips_a_label_and_conditional_branch() {
+            assert_eq!(parse_line("loop:", 1).unwrap(), Instruction::Label("loop".to_string()));
+            assert_eq!(
+                parse_line("BNE loop", 1).unwrap(),
+                Instruction::Branch { cond: Condition::Ne, label: "loop".to_string() }
+            );
+        }
+
+        #[test]
+        fn round_trips_a_vector_instruction_with_width_suffix() {
+            assert_eq!(
+                parse_line("VADD.W v0 v1 v2", 1).unwrap(),
+                Instruction::Vadd { vd: 0, va: 1, vb: 2, width: VecWidth::Word }
+            );
+            assert_eq!(
+                parse_line("VADD v0 v1 v2", 1).unwrap(),
+                Instruction::Vadd { vd: 0, va: 1, vb: 2, width: VecWidth::Qword }
+            );
+        }
+
+        #[test]
+        fn rejects_an_out_of_range_scalar_register() {
+            let err = parse_line("MOVI r40 #1", 3).unwrap_err();
+            assert_eq!(err.line, 3);
+            assert!(err.message.contains("r40"), "message was: {}", err.message);
+        }
+
+        #[test]
+        fn rejects_an_out_of_range_vector_register() {
+            let err = parse_line("VDUP v9 r0", 5).unwrap_err();
+            assert_eq!(err.line, 5);
+            assert!(err.message.contains("v9"), "message was: {}", err.message);
+        }
+
+        #[test]
+        fn rejects_an_unknown_opcode() {
+            let err = parse_line("FROBNICATE r1", 7).unwrap_err();
+            assert_eq!(err.line, 7);
+        }
+    }
+}
+
+// Assembler preprocessor: expands `.define NAME value` symbolic constants and
+// `.macro NAME arg0, arg1 ... .endm` blocks before the label-resolution pass
+// sees the program. Runs purely on source text, ahead of the real parser.
+mod preprocessor {
+    use std::collections::HashMap;
+    use std::fmt;
+
+    const MAX_EXPANSION_DEPTH: usize = 64;
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct PreprocessError {
+        pub line: usize,
+        pub message: String,
+    }
+
+    impl fmt::Display for PreprocessError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "line {}: {}", self.line, self.message)
+        }
+    }
+
+    impl std::error::Error for PreprocessError {}
+
+    struct MacroDef {
+        params: Vec<String>,
+        body: Vec<String>,
+    }
+
+    /// Replaces whole identifier tokens found in `substitutions` with their
+    /// mapped value, leaving everything else (opcodes, punctuation, register
+    /// tokens that aren't keys) untouched.
+    fn substitute_identifiers(line: &str, substitutions: &HashMap<String, String>) -> String {
+        let mut out = String::with_capacity(line.len());
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            if chars[i].is_alphanumeric() || chars[i] == '_' {
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                let token: String = chars[start..i].iter().collect();
+                match substitutions.get(&token) {
+                    Some(value) => out.push_str(value),
+                    None => out.push_str(&token),
+                }
+            } else {
+                out.push(chars[i]);
+                i += 1;
+            }
+        }
+        out
+    }
+
+    fn expand_line(
+        line: &str,
+        lineno: usize,
+        macros: &HashMap<String, MacroDef>,
+        defines: &HashMap<String, String>,
+        depth: usize,
+        out: &mut Vec<String>,
+    ) -> Result<(), PreprocessError> {
+        if depth > MAX_EXPANSION_DEPTH {
+            return Err(PreprocessError {
+                line: lineno,
+                message: "macro expansion exceeded max depth (recursive macro loop?)".to_string(),
+            });
+        }
+
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.ends_with(':') {
+            out.push(substitute_identifiers(line, defines));
+            return Ok(());
+        }
+
+        let name = trimmed
+            .split_whitespace()
+            .next()
+            .unwrap_or("")
+            .to_uppercase();
+
+        if let Some(mac) = macros.get(&name) {
+            let args_str = trimmed[name.len().min(trimmed.len())..].trim();
+            let args: Vec<&str> = if args_str.is_empty() {
+                Vec::new()
+            } else {
+                args_str.split(',').map(|s| s.trim()).collect()
+            };
+            if args.len() != mac.params.len() {
+                return Err(PreprocessError {
+                    line: lineno,
+                    message: format!(
+                        "macro '{}' expects {} argument(s), got {}",
+                        name,
+                        mac.params.len(),
+                        args.len()
+                    ),
+                });
+            }
+            let bindings: HashMap<String, String> = mac
+                .params
+                .iter()
+                .cloned()
+                .zip(args.iter().map(|s| s.to_string()))
+                .collect();
+            for body_line in &mac.body {
+                let substituted = substitute_identifiers(body_line, &bindings);
+                expand_line(&substituted, lineno, macros, defines, depth + 1, out)?;
+            }
+        } else {
+            out.push(substitute_identifiers(line, defines));
+        }
+        Ok(())
+    }
+
+    /// Expands `.define`/`.macro` directives, returning the fully-expanded
+    /// instruction stream that feeds label resolution and parsing.
+    pub fn expand(program: &[String]) -> Result<Vec<String>, PreprocessError> {
+        let mut defines: HashMap<String, String> = HashMap::new();
+        let mut macros: HashMap<String, MacroDef> = HashMap::new();
+        let mut body: Vec<(usize, String)> = Vec::new();
+
+        let mut i = 0;
+        while i < program.len() {
+            let lineno = i + 1;
+            let line = program[i].trim();
+
+            if let Some(rest) = line.strip_prefix(".define") {
+                let mut parts = rest.split_whitespace();
+                let name = parts.next().ok_or_else(|| PreprocessError {
+                    line: lineno,
+                    message: "'.define' requires a name".to_string(),
+                })?;
+                let value = parts.next().ok_or_else(|| PreprocessError {
+                    line: lineno,
+                    message: format!("'.define {}' requires a value", name),
+                })?;
+                defines.insert(name.to_string(), value.to_string());
+                i += 1;
+            } else if let Some(rest) = line.strip_prefix(".macro") {
+                let mut header = rest.trim().splitn(2, char::is_whitespace);
+                let name = header
+                    .next()
+                    .filter(|s| !s.is_empty())
+                    .ok_or_else(|| PreprocessError {
+                        line: lineno,
+                        message: "'.macro' requires a name".to_string(),
+                    })?
+                    .to_uppercase();
+                let params: Vec<String> = header
+                    .next()
+                    .unwrap_or("")
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect();
+
+                let mut body_lines = Vec::new();
+                i += 1;
+                loop {
+                    if i >= program.len() {
+                        return Err(PreprocessError {
+                            line: lineno,
+                            message: format!("'.macro {}' missing '.endm'", name),
+                        });
+                    }
+                    if program[i].trim() == ".endm" {
+                        i += 1;
+                        break;
+                    }
+                    body_lines.push(program[i].clone());
+                    i += 1;
+                }
+                macros.insert(name, MacroDef { params, body: body_lines });
+            } else if line.is_empty() {
+                i += 1;
+            } else {
+                body.push((lineno, program[i].clone()));
+                i += 1;
+            }
+        }
+
+        let mut expanded = Vec::new();
+        for (lineno, line) in body {
+            expand_line(&line, lineno, &macros, &defines, 0, &mut expanded)?;
+        }
+        Ok(expanded)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn substitutes_a_define_everywhere_it_appears() {
+            let program = vec![".define STRIDE 256".to_string(), "MOVI r1 #STRIDE".to_string()];
+            assert_eq!(expand(&program).unwrap(), vec!["MOVI r1 #256".to_string()]);
+        }
+
+        #[test]
+        fn expands_a_macro_with_parameter_substitution() {
+            let program = vec![
+                ".macro INC dst".to_string(),
+                "ADD dst dst #1".to_string(),
+                ".endm".to_string(),
+                "INC r1".to_string(),
+            ];
+            assert_eq!(expand(&program).unwrap(), vec!["ADD r1 r1 #1".to_string()]);
+        }
+
+        #[test]
+        fn rejects_a_macro_call_with_the_wrong_argument_count() {
+            let program = vec![
+                ".macro INC dst".to_string(),
+                "ADD dst dst #1".to_string(),
+                ".endm".to_string(),
+                "INC r1, r2".to_string(),
+            ];
+            let err = expand(&program).unwrap_err();
+            assert_eq!(err.line, 4);
+        }
+
+        #[test]
+        fn rejects_a_macro_missing_its_endm() {
+            let program = vec![".macro INC dst".to_string(), "ADD dst dst #1".to_string()];
+            let err = expand(&program).unwrap_err();
+            assert_eq!(err.line, 1);
+        }
+
+        #[test]
+        fn rejects_runaway_macro_recursion() {
+            let program = vec![
+                ".macro LOOP".to_string(),
+                "LOOP".to_string(),
+                ".endm".to_string(),
+                "LOOP".to_string(),
+            ];
+            let err = expand(&program).unwrap_err();
+            assert!(err.message.contains("max depth"), "message was: {}", err.message);
+        }
+    }
+}
+
+// Inter-core message passing for `SND`/`RCV`: a small fixed-capacity queue
+// per core plus a scheduler that notices when every live core is parked in
+// `RCV` with nothing left to receive, so `run_cores` can terminate instead of
+// hanging forever.
+mod scheduler {
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+    /// The bounded inbox of a single core.
+    pub struct Channel {
+        queue: Mutex<VecDeque<u64>>,
+        capacity: usize,
+    }
+
+    impl Channel {
+        pub fn new(capacity: usize) -> Self {
+            Channel { queue: Mutex::new(VecDeque::new()), capacity }
+        }
+
+        /// Enqueues `value` if there's room, reporting success so callers can
+        /// poll-and-park (see [`Scheduler::send`]) instead of blocking here.
+        fn try_send(&self, value: u64) -> bool {
+            let mut q = self.queue.lock().unwrap();
+            if q.len() < self.capacity {
+                q.push_back(value);
+                true
+            } else {
+                false
+            }
+        }
+
+        pub fn try_recv(&self) -> Option<u64> {
+            self.queue.lock().unwrap().pop_front()
+        }
+    }
+
+    /// Tracks which cores are currently parked — in `RCV` on an empty inbox,
+    /// or in `SND` on a full outbox — so a global deadlock (every live core
+    /// parked) can be detected instead of the VM hanging forever. A parked
+    /// core can only be unparked by a *running* one draining or filling a
+    /// channel, so once every live core is parked simultaneously nothing can
+    /// ever change again. A successful send or receive clears, under the same
+    /// lock, the flag of the core on the other end of that channel (its ring
+    /// neighbour), so a stale flag never hides a waiting value or a free slot.
+    pub struct Scheduler {
+        blocked: Mutex<Vec<bool>>,
+        finished: Mutex<Vec<bool>>,
+    }
+
+    impl Scheduler {
+        pub fn new(cores: usize) -> Self {
+            Scheduler {
+                blocked: Mutex::new(vec![false; cores]),
+                finished: Mutex::new(vec![false; cores]),
+            }
+        }
+
+        pub fn mark_finished(&self, core: usize) {
+            self.finished.lock().unwrap()[core] = true;
+            self.blocked.lock().unwrap()[core] = false;
+        }
+
+        fn all_parked(&self) -> bool {
+            let finished = self.finished.lock().unwrap();
+            let blocked = self.blocked.lock().unwrap();
+            let all_parked = blocked.iter().zip(finished.iter()).all(|(&b, &f)| f || b);
+            let any_live = finished.iter().any(|&f| !f);
+            any_live && all_parked
+        }
+
+        /// Parks `core` on `inbox` until a value arrives or a global
+        /// deadlock is detected, in which case `None` is returned.
+        pub fn recv(&self, core: usize, inbox: &Channel) -> Option<u64> {
+            loop {
+                {
+                    let mut blocked = self.blocked.lock().unwrap();
+                    if let Some(v) = inbox.try_recv() {
+                        let n = blocked.len();
+                        blocked[core] = false;
+                        blocked[(core + n - 1) % n] = false; // our sender may have room now
+                        return Some(v);
+                    }
+                    blocked[core] = true;
+                }
+
+                if self.all_parked() {
+                    return None;
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+
+        /// Parks `core` on `outbox` until there's room for `value` or a
+        /// global deadlock is detected, in which case the send is abandoned
+        /// and `false` is returned.
+        pub fn send(&self, core: usize, outbox: &Channel, value: u64) -> bool {
+            loop {
+                {
+                    let mut blocked = self.blocked.lock().unwrap();
+                    if outbox.try_send(value) {
+                        let n = blocked.len();
+                        blocked[core] = false;
+                        blocked[(core + 1) % n] = false; // our receiver has a value now
+                        return true;
+                    }
+                    blocked[core] = true;
+                }
+
+                if self.all_parked() {
+                    return false;
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+use parser::{Condition, Instruction, MemOperand, Operand, ParseError, VecWidth};
+use preprocessor::PreprocessError;
+
+/// Errors that can surface while assembling a program, before the machine
+/// ever starts executing it.
+#[derive(Debug, Clone, PartialEq)]
+enum AssembleError {
+    Preprocess(PreprocessError),
+    Parse(ParseError),
+}
+
+impl std::fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AssembleError::Preprocess(e) => write!(f, "preprocess error: {}", e),
+            AssembleError::Parse(e) => write!(f, "parse error: {}", e),
+        }
+    }
+}
+
+impl From<PreprocessError> for AssembleError {
+    fn from(e: PreprocessError) -> Self {
+        AssembleError::Preprocess(e)
+    }
+}
+
+impl From<ParseError> for AssembleError {
+    fn from(e: ParseError) -> Self {
+        AssembleError::Parse(e)
+    }
+}
+
+/// A full copy of machine state at a given point in the instruction log,
+/// used by [`CRZ64I_Emulator::step_back`] as a cheap rewind point instead of
+/// re-running the whole log from step zero.
 #[derive(Clone)]
+struct Snapshot {
+    regs: [u64; 32],
+    vregs: Vec<[u64; 2]>,
+    memory: Vec<u8>,
+    pc: usize,
+    flags: HashMap<String, u8>,
+    halted: bool,
+    step: usize, // index into `log` this snapshot was taken after
+}
+
+/// Automatic snapshots are taken every `SNAPSHOT_INTERVAL` executed steps, so
+/// `step_back` only ever has to replay at most that many log entries.
+const SNAPSHOT_INTERVAL: usize = 16;
+
+/// A single precompiled instruction, as produced by `compile_one`.
+type CompiledOp = Box<dyn FnMut(&mut CRZ64I_Emulator)>;
+
 struct CRZ64I_Emulator {
     regs: [u64; 32],
     vregs: Vec<[u64; 2]>,
@@ -11,8 +869,14 @@ struct CRZ64I_Emulator {
     pc: usize,
     flags: HashMap<String, u8>,
     halted: bool,
-    labels: HashMap<String, usize>,  // For branches
-    log: Vec<String>,  // For determinism replay
+    labels: HashMap<String, usize>, // For branches
+    log: Vec<String>,               // For determinism replay
+    snapshots: Vec<Snapshot>,       // periodic save-states for step_back
+    core_id: usize,                 // program-local ID for multi-core runs
+    inbox: Option<Arc<scheduler::Channel>>,
+    outbox: Option<Arc<scheduler::Channel>>,
+    sched: Option<Arc<scheduler::Scheduler>>,
+    sent_count: usize,
 }
 
 impl CRZ64I_Emulator {
@@ -32,82 +896,381 @@ impl CRZ64I_Emulator {
             halted: false,
             labels: HashMap::new(),
             log: Vec::new(),
+            snapshots: Vec::new(),
+            core_id: 0,
+            inbox: None,
+            outbox: None,
+            sched: None,
+            sent_count: 0,
+        }
+    }
+
+    fn resolve_mem(&self, mem: &MemOperand) -> usize {
+        match *mem {
+            MemOperand::Base(base) => self.regs[base] as usize,
+            MemOperand::BaseImm(base, off) => (self.regs[base] as i64 + off) as usize,
+            MemOperand::BaseReg(base, off_reg) => {
+                (self.regs[base] as i64 + self.regs[off_reg] as i64) as usize
+            }
+        }
+    }
+
+    /// Applies a lane-wise binary op across a 128-bit vector register pair,
+    /// reinterpreting the same underlying bytes as `width`-sized lanes with
+    /// independent wraparound (truncating the `u64` result to the lane width
+    /// on write-back gives exactly mod-2^n wraparound for add/sub/mul).
+    fn vec_lane_op(a: [u64; 2], b: [u64; 2], width: VecWidth, f: impl Fn(u64, u64) -> u64) -> [u64; 2] {
+        let mut a_bytes = [0u8; 16];
+        a_bytes[0..8].copy_from_slice(&a[0].to_le_bytes());
+        a_bytes[8..16].copy_from_slice(&a[1].to_le_bytes());
+        let mut b_bytes = [0u8; 16];
+        b_bytes[0..8].copy_from_slice(&b[0].to_le_bytes());
+        b_bytes[8..16].copy_from_slice(&b[1].to_le_bytes());
+
+        let lane_bytes = width.lane_bytes();
+        let mut out_bytes = [0u8; 16];
+        for lane in (0..16).step_by(lane_bytes) {
+            let mut a_buf = [0u8; 8];
+            let mut b_buf = [0u8; 8];
+            a_buf[..lane_bytes].copy_from_slice(&a_bytes[lane..lane + lane_bytes]);
+            b_buf[..lane_bytes].copy_from_slice(&b_bytes[lane..lane + lane_bytes]);
+            let result = f(u64::from_le_bytes(a_buf), u64::from_le_bytes(b_buf));
+            out_bytes[lane..lane + lane_bytes].copy_from_slice(&result.to_le_bytes()[..lane_bytes]);
         }
+
+        [
+            u64::from_le_bytes(out_bytes[0..8].try_into().unwrap()),
+            u64::from_le_bytes(out_bytes[8..16].try_into().unwrap()),
+        ]
+    }
+
+    fn set_flags(&mut self, result: u64, carry: bool, overflow: bool) {
+        self.flags.insert("Z".to_string(), if result == 0 { 1 } else { 0 });
+        self.flags
+            .insert("N".to_string(), if (result & (1u64 << 63)) != 0 { 1 } else { 0 });
+        self.flags.insert("C".to_string(), if carry { 1 } else { 0 });
+        self.flags.insert("V".to_string(), if overflow { 1 } else { 0 });
     }
 
-    fn parse_reg(&self, s: &str) -> usize {
-        s.trim_matches(|c: char| c == 'r' || c == ',' || c.is_whitespace()).parse().unwrap_or(0)
-    }
-
-    fn execute(&mut self, instr: &str) {
-        self.log.push(instr.to_string());  // Log for replay
-        let re = Regex::new(r"(\w+)\s*(r\d+)?\s*,?\s*(r\d+|\#\d+)?\s*,?\s*(r\d+|\#\d+|\[.*\])?").unwrap();
-        if let Some(caps) = re.captures(instr) {
-            let opcode = caps.get(1).map_or("", |m| m.as_str()).to_uppercase();
-
-            match opcode.as_str() {
-                "MOVI" => {
-                    let rd = self.parse_reg(caps.get(2).map_or("", |m| m.as_str()));
-                    let imm: u64 = caps.get(3).map_or(0, |m| m.as_str().trim_start_matches('#').parse().unwrap());
-                    self.regs[rd] = imm;
-                }
-                "LOAD" => {
-                    let rd = self.parse_reg(caps.get(2).map_or("", |m| m.as_str()));
-                    let addr_str = caps.get(4).map_or("", |m| m.as_str()).trim_matches(|c| c == '[' || c == ']');
-                    let base = self.parse_reg(addr_str.split('+').next().unwrap_or(""));
-                    let off: usize = addr_str.split('+').nth(1).map_or(0, |s| s.parse().unwrap());
-                    let addr = self.regs[base] as usize + off;
-                    let mut mem = self.memory.lock().unwrap();
-                    if addr + 8 > mem.len() { panic!("Out of bounds"); }
-                    let value = u64::from_le_bytes(mem[addr..addr+8].try_into().unwrap());
-                    self.regs[rd] = value;
-                }
-                "ADD" => {
-                    let rd = self.parse_reg(caps.get(2).map_or("", |m| m.as_str()));
-                    let rs1 = self.parse_reg(caps.get(3).map_or("", |m| m.as_str()));
-                    let op2_str = caps.get(4).map_or("", |m| m.as_str());
-                    let op2 = if op2_str.starts_with('#') { op2_str[1..].parse::<u64>().unwrap() } else { self.regs[self.parse_reg(op2_str)] };
-                    let a = self.regs[rs1];
-                    let (sum, carry) = a.overflowing_add(op2);
-                    let v = ((a ^ sum) & (op2 ^ sum) & (1u64 << 63)) != 0;  // Signed overflow
-                    self.regs[rd] = sum;
-                    self.flags.insert("Z".to_string(), if sum == 0 {1} else {0});
-                    self.flags.insert("N".to_string(), if (sum & (1u64 << 63)) != 0 {1} else {0});
-                    self.flags.insert("C".to_string(), if carry {1} else {0});
-                    self.flags.insert("V".to_string(), if v {1} else {0});
-                }
-                "BNE" => {
-                    let label = caps.get(2).map_or("", |m| m.as_str());
-                    if self.flags["Z"] == 0 {
-                        self.pc = *self.labels.get(label).unwrap_or(&0) - 1;  // Adjust for pc+=1
+    /// Evaluates a condition against the current `Z`/`N`/`C`/`V` flags, used
+    /// both by predicated data-processing ops and conditional branches.
+    fn condition_holds(&self, cond: Condition) -> bool {
+        let z = self.flags["Z"] == 1;
+        let n = self.flags["N"] == 1;
+        let c = self.flags["C"] == 1;
+        let v = self.flags["V"] == 1;
+        match cond {
+            Condition::Always => true,
+            Condition::Eq => z,
+            Condition::Ne => !z,
+            Condition::Gt => !z && (n == v),
+            Condition::Ge => n == v,
+            Condition::Lt => n != v,
+            Condition::Le => z || (n != v),
+            Condition::Hi => c && !z,
+            Condition::Lo => !c,
+        }
+    }
+
+    /// Captures full machine state so it can later be restored by
+    /// [`restore`](Self::restore).
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            regs: self.regs,
+            vregs: self.vregs.clone(),
+            memory: self.memory.lock().unwrap().clone(),
+            pc: self.pc,
+            flags: self.flags.clone(),
+            halted: self.halted,
+            step: self.log.len(),
+        }
+    }
+
+    /// Overwrites machine state with a previously captured [`Snapshot`].
+    fn restore(&mut self, snap: &Snapshot) {
+        self.regs = snap.regs;
+        self.vregs = snap.vregs.clone();
+        *self.memory.lock().unwrap() = snap.memory.clone();
+        self.pc = snap.pc;
+        self.flags = snap.flags.clone();
+        self.halted = snap.halted;
+    }
+
+    /// Rewinds the machine by `n` executed steps: restores the most recent
+    /// snapshot at or before the target step, then replays the logged
+    /// instructions between that snapshot and the target. With automatic
+    /// snapshots every `SNAPSHOT_INTERVAL` steps this replay is O(K) rather
+    /// than O(total steps executed). Log entries and snapshots past the
+    /// target are discarded, so a further `step_back` counts from there.
+    fn step_back(&mut self, n: usize) {
+        let target_step = self.log.len().saturating_sub(n);
+        let snap = self
+            .snapshots
+            .iter()
+            .rev()
+            .find(|s| s.step <= target_step)
+            .cloned()
+            .expect("step_back called before any snapshot was taken");
+
+        self.restore(&snap);
+        let replay_lines = self.log[snap.step..target_step].to_vec();
+        self.log.truncate(target_step);
+        self.snapshots.retain(|s| s.step <= target_step);
+        for line in &replay_lines {
+            let instr = parser::parse_line(line, 0).expect("log entries were valid instructions");
+            self.execute(&instr);
+            self.pc += 1;
+        }
+    }
+
+    /// Deterministically reproduces a prior run from a saved instruction
+    /// trace, returning the resulting machine state. Unlike `run_program`,
+    /// this replays a flat log of already-executed lines rather than a
+    /// labelled program, so branch targets are resolved relative to an
+    /// empty label table and simply fall through if unresolved.
+    fn replay(log: &[String]) -> Result<Self, ParseError> {
+        let mut emulator = Self::new();
+        for (i, line) in log.iter().enumerate() {
+            let instr = parser::parse_line(line, i + 1)?;
+            emulator.execute(&instr);
+            emulator.pc += 1;
+            emulator.log.push(line.clone());
+        }
+        Ok(emulator)
+    }
+
+    /// Executes one instruction by lowering it through [`compile_one`](Self::compile_one)
+    /// and immediately invoking the result. This is the slow, one-off path
+    /// used by `step_back`/`replay`; `run_program`'s hot loop instead calls
+    /// `compile` once and reuses the compiled ops. Routing both through
+    /// `compile_one` keeps instruction semantics defined in exactly one
+    /// place instead of drifting between two copies.
+    fn execute(&mut self, instr: &Instruction) {
+        let mut op = self.compile_one(instr);
+        op(self);
+    }
+
+    /// Lowers a single parsed `Instruction` into a boxed closure that
+    /// performs its effect directly on the emulator. Everything knowable
+    /// ahead of time is resolved once, here, rather than on every call:
+    /// register indices and immediates are captured by value, `Operand`
+    /// (reg vs. immediate) is matched once into a specialized closure, branch
+    /// targets are resolved against `self.labels`, and `SND`/`RCV` resolve
+    /// their channel handles up front. The run loop then just calls
+    /// `compiled[pc]` with no decode, no `Instruction` re-match, and no
+    /// label `HashMap` lookup per branch; flag tests and updates still go
+    /// through the `flags` map.
+    fn compile_one(&self, instr: &Instruction) -> CompiledOp {
+        match instr.clone() {
+            Instruction::Label(_) => Box::new(|_: &mut Self| {}),
+            Instruction::Movi { rd, imm, cond } => Box::new(move |e: &mut Self| {
+                if e.condition_holds(cond) {
+                    e.regs[rd] = imm as u64;
+                }
+            }),
+            Instruction::Load { rd, mem } => Box::new(move |e: &mut Self| {
+                let addr = e.resolve_mem(&mem);
+                let value = {
+                    let m = e.memory.lock().unwrap();
+                    if addr + 8 > m.len() {
+                        panic!("Out of bounds");
+                    }
+                    u64::from_le_bytes(m[addr..addr + 8].try_into().unwrap())
+                };
+                e.regs[rd] = value;
+            }),
+            Instruction::Add { rd, rs1, operand, cond } => match operand {
+                Operand::Imm(imm) => {
+                    let op2 = imm as u64;
+                    Box::new(move |e: &mut Self| {
+                        if e.condition_holds(cond) {
+                            Self::add_into(e, rd, rs1, op2);
+                        }
+                    })
+                }
+                Operand::Reg(r) => Box::new(move |e: &mut Self| {
+                    if e.condition_holds(cond) {
+                        let op2 = e.regs[r];
+                        Self::add_into(e, rd, rs1, op2);
                     }
+                }),
+            },
+            Instruction::Cmp { rs1, operand } => match operand {
+                Operand::Imm(imm) => {
+                    let op2 = imm as u64;
+                    Box::new(move |e: &mut Self| Self::cmp(e, rs1, op2))
                 }
-                // Add XCHG, INC, etc. similarly...
-                _ => {}
+                Operand::Reg(r) => Box::new(move |e: &mut Self| {
+                    let op2 = e.regs[r];
+                    Self::cmp(e, rs1, op2);
+                }),
+            },
+            Instruction::Dec { rd } => Box::new(move |e: &mut Self| {
+                let a = e.regs[rd];
+                let (result, borrow) = a.overflowing_sub(1);
+                let v = ((a ^ 1) & (a ^ result) & (1u64 << 63)) != 0; // Signed overflow
+                e.regs[rd] = result;
+                e.set_flags(result, !borrow, v); // C=1 means no borrow (a >= 1 unsigned)
+            }),
+            Instruction::Branch { cond, label } => {
+                let target = self.labels.get(&label).copied();
+                Box::new(move |e: &mut Self| {
+                    if e.condition_holds(cond) {
+                        if let Some(target) = target {
+                            e.pc = target.wrapping_sub(1); // Adjust for pc+=1
+                        } // else: label unresolved — fall through
+                    }
+                })
             }
+            Instruction::Snd { rs } => {
+                let outbox = self.outbox.clone().expect("SND used outside run_cores");
+                let sched = self.sched.clone().expect("SND used outside run_cores");
+                Box::new(move |e: &mut Self| {
+                    if sched.send(e.core_id, &outbox, e.regs[rs]) {
+                        e.sent_count += 1;
+                    } else {
+                        e.halted = true; // global deadlock: nobody left to drain the outbox
+                    }
+                })
+            }
+            Instruction::Rcv { rd } => {
+                let inbox = self.inbox.clone().expect("RCV used outside run_cores");
+                let sched = self.sched.clone().expect("RCV used outside run_cores");
+                Box::new(move |e: &mut Self| match sched.recv(e.core_id, &inbox) {
+                    Some(value) => e.regs[rd] = value,
+                    None => e.halted = true, // global deadlock: nothing left to receive
+                })
+            }
+            Instruction::Vload { vd, mem } => Box::new(move |e: &mut Self| {
+                let addr = e.resolve_mem(&mem);
+                let m = e.memory.lock().unwrap();
+                if addr + 16 > m.len() {
+                    panic!("Out of bounds");
+                }
+                let lo = u64::from_le_bytes(m[addr..addr + 8].try_into().unwrap());
+                let hi = u64::from_le_bytes(m[addr + 8..addr + 16].try_into().unwrap());
+                drop(m);
+                e.vregs[vd] = [lo, hi];
+            }),
+            Instruction::Vstore { vs, mem } => Box::new(move |e: &mut Self| {
+                let addr = e.resolve_mem(&mem);
+                let mut m = e.memory.lock().unwrap();
+                if addr + 16 > m.len() {
+                    panic!("Out of bounds");
+                }
+                let [lo, hi] = e.vregs[vs];
+                m[addr..addr + 8].copy_from_slice(&lo.to_le_bytes());
+                m[addr + 8..addr + 16].copy_from_slice(&hi.to_le_bytes());
+            }),
+            Instruction::Vdup { vd, rs } => Box::new(move |e: &mut Self| {
+                e.vregs[vd] = [e.regs[rs], e.regs[rs]];
+            }),
+            Instruction::Vadd { vd, va, vb, width } => Box::new(move |e: &mut Self| {
+                e.vregs[vd] = Self::vec_lane_op(e.vregs[va], e.vregs[vb], width, |a, b| a.wrapping_add(b));
+            }),
+            Instruction::Vsub { vd, va, vb, width } => Box::new(move |e: &mut Self| {
+                e.vregs[vd] = Self::vec_lane_op(e.vregs[va], e.vregs[vb], width, |a, b| a.wrapping_sub(b));
+            }),
+            Instruction::Vmul { vd, va, vb, width } => Box::new(move |e: &mut Self| {
+                e.vregs[vd] = Self::vec_lane_op(e.vregs[va], e.vregs[vb], width, |a, b| a.wrapping_mul(b));
+            }),
         }
     }
 
-    fn run_program(&mut self, program: Vec<String>) {
-        // Build labels first
-        for (i, instr) in program.iter().enumerate() {
-            if instr.ends_with(':') {
-                self.labels.insert(instr.trim_end_matches(':').to_string(), i);
+    fn add_into(&mut self, rd: usize, rs1: usize, op2: u64) {
+        let a = self.regs[rs1];
+        let (sum, carry) = a.overflowing_add(op2);
+        let v = ((a ^ sum) & (op2 ^ sum) & (1u64 << 63)) != 0; // Signed overflow
+        self.regs[rd] = sum;
+        self.set_flags(sum, carry, v);
+    }
+
+    fn cmp(&mut self, rs1: usize, op2: u64) {
+        let a = self.regs[rs1];
+        let (diff, borrow) = a.overflowing_sub(op2);
+        let v = ((a ^ op2) & (a ^ diff) & (1u64 << 63)) != 0; // Signed overflow
+        self.set_flags(diff, !borrow, v); // C=1 means no borrow (a >= b unsigned)
+    }
+
+    /// Compiles a parsed program into an indexable op table: decode happens
+    /// once here, and the run loop below just calls `compiled[pc]`.
+    fn compile(&self, instructions: &[Instruction]) -> Vec<CompiledOp> {
+        instructions.iter().map(|instr| self.compile_one(instr)).collect()
+    }
+
+    fn run_program(&mut self, program: Vec<String>) -> Result<(), AssembleError> {
+        let expanded = preprocessor::expand(&program)?;
+
+        let instructions: Vec<Instruction> = expanded
+            .iter()
+            .enumerate()
+            .map(|(i, line)| parser::parse_line(line, i + 1))
+            .collect::<Result<_, _>>()?;
+
+        for (i, instr) in instructions.iter().enumerate() {
+            if let Instruction::Label(name) = instr {
+                self.labels.insert(name.clone(), i);
             }
         }
-        while self.pc < program.len() && !self.halted {
-            self.execute(&program[self.pc]);
+
+        let mut compiled = self.compile(&instructions);
+
+        self.snapshots.push(self.snapshot());
+        while self.pc < compiled.len() && !self.halted {
+            self.log.push(expanded[self.pc].clone()); // record the resolved (post-macro) op for replay
+            compiled[self.pc](self);
             self.pc += 1;
+            if self.log.len().is_multiple_of(SNAPSHOT_INTERVAL) {
+                self.snapshots.push(self.snapshot());
+            }
         }
+        Ok(())
     }
 }
 
+/// Runs one `CRZ64I_Emulator` per program, each on its own thread, wired
+/// into a ring of channels so core `i`'s `SND` lands in core `(i+1) % N`'s
+/// inbox and its `RCV` drains its own. Returns how many values each core
+/// sent before halting (normally or via global deadlock).
+fn run_cores(programs: Vec<Vec<String>>) -> Vec<usize> {
+    let cores = programs.len();
+    let channels: Vec<Arc<scheduler::Channel>> = (0..cores)
+        .map(|_| Arc::new(scheduler::Channel::new(16)))
+        .collect();
+    let sched = Arc::new(scheduler::Scheduler::new(cores));
+
+    let handles: Vec<_> = programs
+        .into_iter()
+        .enumerate()
+        .map(|(i, program)| {
+            let inbox = channels[i].clone();
+            let outbox = channels[(i + 1) % cores].clone();
+            let sched = sched.clone();
+            thread::spawn(move || {
+                let mut core = CRZ64I_Emulator::new();
+                core.core_id = i;
+                core.inbox = Some(inbox);
+                core.outbox = Some(outbox);
+                core.sched = Some(sched.clone());
+                if let Err(e) = core.run_program(program) {
+                    eprintln!("core {}: {}", i, e);
+                }
+                sched.mark_finished(i);
+                core.sent_count
+            })
+        })
+        .collect();
+
+    handles.into_iter().map(|h| h.join().unwrap()).collect()
+}
+
 fn main() {
     let mut emulator = CRZ64I_Emulator::new();
     // Set memory...
     // Program with loop label
     let program = vec![
-        "MOVI r1 #256".to_string(),
+        ".define STRIDE 256".to_string(),
+        "MOVI r1 #STRIDE".to_string(),
         "MOVI r2 #4".to_string(),
         "MOVI r3 #0".to_string(),
         "loop:".to_string(),
@@ -115,8 +1278,203 @@ fn main() {
         "ADD r3 r3 r4".to_string(),
         "ADD r1 r1 #8".to_string(),
         "DEC r2".to_string(),
-        "BNE loop".to_string(),  // Now works
+        "BNE loop".to_string(), // terminates once DEC r2 sets Z
     ];
-    emulator.run_program(program);
+    if let Err(e) = emulator.run_program(program) {
+        eprintln!("{}", e);
+        return;
+    }
     println!("Sum: {}", emulator.regs[3]);
-}
\ No newline at end of file
+
+    // Two-core producer/consumer demo: core 0 sends a value to core 1, which
+    // increments it and sends it straight back around the ring.
+    let counts = run_cores(vec![
+        vec!["MOVI r1 #42".to_string(), "SND r1".to_string(), "RCV r2".to_string()],
+        vec!["RCV r1".to_string(), "ADD r1 r1 #1".to_string(), "SND r1".to_string()],
+    ]);
+    println!("core send counts: {:?}", counts);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cmp_and_bne_drive_a_counting_loop() {
+        let mut e = CRZ64I_Emulator::new();
+        e.run_program(vec![
+            "MOVI r1 #0".to_string(),
+            "MOVI r2 #5".to_string(),
+            "loop:".to_string(),
+            "ADD r1 r1 #1".to_string(),
+            "CMP r1 r2".to_string(),
+            "BNE loop".to_string(),
+        ])
+        .unwrap();
+        assert_eq!(e.regs[1], 5);
+    }
+
+    #[test]
+    fn predicated_ops_only_fire_when_their_condition_holds() {
+        let mut e = CRZ64I_Emulator::new();
+        e.run_program(vec![
+            "MOVI r1 #3".to_string(),
+            "CMP r1 #3".to_string(), // Z=1
+            "MOVIEQ r2 #99".to_string(),
+            "MOVINE r3 #99".to_string(),
+            "ADDEQ r4 r1 #1".to_string(),
+        ])
+        .unwrap();
+        assert_eq!(e.regs[2], 99, "MOVIEQ should have fired");
+        assert_eq!(e.regs[3], 0, "MOVINE should not have fired");
+        assert_eq!(e.regs[4], 4, "ADDEQ should have fired");
+    }
+
+    #[test]
+    fn compiled_add_resolves_both_immediate_and_register_operands() {
+        let mut e = CRZ64I_Emulator::new();
+        e.run_program(vec![
+            "MOVI r1 #10".to_string(),
+            "MOVI r2 #5".to_string(),
+            "ADD r3 r1 #2".to_string(), // Add::Imm closure
+            "ADD r4 r1 r2".to_string(), // Add::Reg closure
+        ])
+        .unwrap();
+        assert_eq!(e.regs[3], 12);
+        assert_eq!(e.regs[4], 15);
+    }
+
+    #[test]
+    fn bgt_is_taken_when_signed_greater() {
+        let mut e = CRZ64I_Emulator::new();
+        e.run_program(vec![
+            "MOVI r1 #5".to_string(),
+            "CMP r1 #3".to_string(), // 5 > 3
+            "BGT greater".to_string(),
+            "MOVI r9 #1".to_string(), // should be skipped
+            "greater:".to_string(),
+            "MOVI r2 #111".to_string(),
+        ])
+        .unwrap();
+        assert_eq!(e.regs[9], 0);
+        assert_eq!(e.regs[2], 111);
+    }
+
+    #[test]
+    fn step_back_rewinds_to_a_prior_register_value() {
+        let mut e = CRZ64I_Emulator::new();
+        e.run_program(vec![
+            "MOVI r1 #1".to_string(),
+            "MOVI r1 #2".to_string(),
+            "MOVI r1 #3".to_string(),
+        ])
+        .unwrap();
+        assert_eq!(e.regs[1], 3);
+        e.step_back(1);
+        assert_eq!(e.regs[1], 2);
+        e.step_back(2);
+        assert_eq!(e.regs[1], 0);
+    }
+
+    #[test]
+    fn step_back_spans_an_automatic_snapshot_boundary() {
+        let mut e = CRZ64I_Emulator::new();
+        let mut program: Vec<String> = (0..(SNAPSHOT_INTERVAL * 2 + 3))
+            .map(|i| format!("MOVI r1 #{}", i))
+            .collect();
+        let last = program.len() - 1;
+        program.push("MOVI r2 #999".to_string());
+        e.run_program(program).unwrap();
+        e.step_back(1);
+        assert_eq!(e.regs[1], last as i64 as u64);
+        assert_eq!(e.regs[2], 0);
+    }
+
+    #[test]
+    fn replay_reproduces_a_logged_trace_from_scratch() {
+        let mut original = CRZ64I_Emulator::new();
+        original
+            .run_program(vec![
+                "MOVI r1 #0".to_string(),
+                "MOVI r2 #3".to_string(),
+                "loop:".to_string(),
+                "ADD r1 r1 #1".to_string(),
+                "DEC r2".to_string(),
+                "BNE loop".to_string(),
+            ])
+            .unwrap();
+
+        let replayed = CRZ64I_Emulator::replay(&original.log).unwrap();
+        assert_eq!(replayed.regs[1], original.regs[1]);
+    }
+
+    #[test]
+    fn replay_falls_through_instead_of_panicking_on_an_unresolved_branch() {
+        let e = CRZ64I_Emulator::replay(&["MOVI r1 #0".to_string(), "BNE nowhere".to_string()]).unwrap();
+        assert_eq!(e.pc, 2);
+    }
+
+    #[test]
+    fn run_cores_round_trips_a_value_around_the_ring() {
+        let counts = run_cores(vec![
+            vec!["MOVI r1 #42".to_string(), "SND r1".to_string(), "RCV r2".to_string()],
+            vec!["RCV r1".to_string(), "ADD r1 r1 #1".to_string(), "SND r1".to_string()],
+        ]);
+        assert_eq!(counts, vec![1, 1]);
+    }
+
+    #[test]
+    fn run_cores_detects_a_send_blocked_deadlock_instead_of_hanging() {
+        // Core 0 tries to send more values than the channel can hold, and
+        // nothing ever drains it, so its SND loop must detect the global
+        // deadlock instead of polling forever.
+        let mut sender = vec!["MOVI r1 #1".to_string()];
+        sender.extend(std::iter::repeat("SND r1".to_string()).take(20));
+        let counts = run_cores(vec![sender, vec![]]);
+        assert_eq!(counts[0], 16); // capacity-16 channel fills, then the rest are abandoned
+        assert_eq!(counts[1], 0);
+    }
+
+    #[test]
+    fn vdup_and_vadd_operate_lane_wise() {
+        let mut e = CRZ64I_Emulator::new();
+        e.run_program(vec![
+            "MOVI r1 #5".to_string(),
+            "VDUP v0 r1".to_string(),
+            "VADD v1 v0 v0".to_string(), // default width: two u64 lanes
+        ])
+        .unwrap();
+        assert_eq!(e.vregs[0], [5, 5]);
+        assert_eq!(e.vregs[1], [10, 10]);
+    }
+
+    #[test]
+    fn vsub_and_vmul_wrap_within_the_requested_lane_width() {
+        let mut e = CRZ64I_Emulator::new();
+        e.run_program(vec![
+            "MOVI r1 #0".to_string(),
+            "VDUP v0 r1".to_string(), // [0, 0]
+            "MOVI r2 #1".to_string(),
+            "VDUP v1 r2".to_string(), // [1, 1]
+            "VSUB.B v2 v0 v1".to_string(), // byte-lane wraparound: 0 - 1 == 0xFF per byte
+            "VMUL.H v3 v1 v1".to_string(),
+        ])
+        .unwrap();
+        assert_eq!(e.vregs[2], [0xFF, 0xFF]); // only the lowest byte lane of each half is nonzero
+        assert_eq!(e.vregs[3], [1, 1]); // 1*1 in the lowest halfword lane, zero elsewhere
+    }
+
+    #[test]
+    fn vload_and_vstore_round_trip_through_memory() {
+        let mut e = CRZ64I_Emulator::new();
+        e.run_program(vec![
+            "MOVI r1 #0".to_string(),
+            "MOVI r2 #7".to_string(),
+            "VDUP v0 r2".to_string(),
+            "VSTORE v0 [r1]".to_string(),
+            "VLOAD v1 [r1]".to_string(),
+        ])
+        .unwrap();
+        assert_eq!(e.vregs[1], [7, 7]);
+    }
+}